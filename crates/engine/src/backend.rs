@@ -0,0 +1,83 @@
+use std::fs::{create_dir_all, read_dir, remove_file, File};
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+
+/// A segment reader that also supports seeking, so a backend can serve a block
+/// at an arbitrary offset (via the local sparse index) without reading the whole
+/// object. Blanket-implemented for any `Read + Seek + Send` type, e.g. [`File`].
+pub trait SegmentReader: Read + Seek + Send {}
+impl<T: Read + Seek + Send> SegmentReader for T {}
+
+/// Abstracts where segment files live so segments can be stored somewhere other
+/// than the local `path` — for example a remote object store — without the rest
+/// of the store caring which. The store's own segment reads, writes and listing
+/// go through this trait. The compaction loop is handed the store directory, not
+/// a backend, so the segments it merges and retires are not handled through it.
+///
+/// Readers are seekable ([`SegmentReader`]) so a remote backend can fetch only
+/// the blocks a lookup needs while keeping the sparse index local. The remote
+/// object-storage backend itself and bounded-buffer streaming compaction are
+/// tracked as a separate backlog item (`chunk0-6-remote`); [`FilesystemBackend`]
+/// is the only backend implemented today.
+pub trait Backend: Send + Sync {
+    /// Open the named segment for seekable reads.
+    fn open(&self, name: &str) -> std::io::Result<Box<dyn SegmentReader>>;
+
+    /// Create (or truncate) the named segment and return a streaming writer.
+    fn create(&self, name: &str) -> std::io::Result<Box<dyn Write + Send>>;
+
+    /// List the names of every segment the backend currently holds.
+    fn list(&self) -> Vec<String>;
+
+    /// Remove the named segment.
+    fn delete(&self, name: &str) -> std::io::Result<()>;
+}
+
+/// The default [`Backend`], storing segments as files under a local directory.
+pub struct FilesystemBackend {
+    root: PathBuf,
+}
+
+impl FilesystemBackend {
+    /// Create a filesystem backend rooted at `root`, creating the directory if
+    /// it does not already exist.
+    pub fn new(root: PathBuf) -> Self {
+        if !root.exists() {
+            create_dir_all(&root).unwrap();
+        }
+        Self { root }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+impl Backend for FilesystemBackend {
+    fn open(&self, name: &str) -> std::io::Result<Box<dyn SegmentReader>> {
+        Ok(Box::new(File::open(self.path(name))?))
+    }
+
+    fn create(&self, name: &str) -> std::io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(File::create(self.path(name))?))
+    }
+
+    fn list(&self) -> Vec<String> {
+        let Ok(entries) = read_dir(&self.root) else {
+            return Vec::new();
+        };
+        entries
+            .filter_map(Result::ok)
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn delete(&self, name: &str) -> std::io::Result<()> {
+        remove_file(self.path(name))
+    }
+}
+
+/// Return the segment filename for the given id.
+pub fn segment_name(id: u64) -> String {
+    format!("segment-{id}.dat")
+}
@@ -1,3 +1,4 @@
+pub mod backend;
 pub mod compaction;
 pub mod engine;
 pub mod env;